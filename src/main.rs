@@ -1,100 +1,276 @@
 use anyhow::{anyhow, Context};
+use clap::{Parser, Subcommand};
 use evdev::{
     raw_stream::{self, RawDevice},
     uinput::{VirtualDevice, VirtualDeviceBuilder},
-    AttributeSet, InputEvent, InputEventKind, Key, RelativeAxisType,
+    AttributeSet, BusType, EventType, InputEvent, InputEventKind, InputId, Key, RelativeAxisType,
+    Synchronization,
 };
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
 use serde::Deserialize;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+mod script;
+use script::Script;
+
+/// Fold a physical keyboard/mouse into a virtual device.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Cli {
+    /// Path to the YAML config file
+    #[arg(short, long, default_value = "config.yml")]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Enumerate input devices and their capabilities, then exit
+    ListDevices,
+}
 
 #[derive(Debug, PartialEq)]
-struct Keystroke {
-    key: u16,
-    value: i32,
+pub(crate) struct Keystroke {
+    pub(crate) key: u16,
+    pub(crate) value: i32,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Copy, Clone)]
-enum Mode {
-    Mouse,
-    Manipulator,
+// What an input `(key, value)` pair should be turned into. Distinct from
+// `evdev::InputEvent` because it has to be deserializable from the config.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputEvent {
+    Key { code: String, value: i32 },
+    RelAxis { code: String, value: i32 },
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
-struct Config {
-    target_name: String,
-    virtual_manipulator_prefix: String,
-    virtual_mouse_prefix: String,
-    virtual_mouse_keys: Vec<String>,
-    virtual_mouse_axes: Vec<String>,
+impl OutputEvent {
+    fn to_input_event(&self) -> Result<InputEvent, anyhow::Error> {
+        match self {
+            OutputEvent::Key { code, value } => {
+                let key = Key::from_str(code)
+                    .map_err(|_| anyhow!("event \"{}\" doesn't exist", code))?;
+                Ok(InputEvent::new(EventType::KEY, key.code(), *value))
+            }
+            OutputEvent::RelAxis { code, value } => {
+                let axis = RelativeAxisType::from_str(code)
+                    .map_err(|_| anyhow!("event \"{}\" doesn't exist", code))?;
+                Ok(InputEvent::new(EventType::RELATIVE, axis.0, *value))
+            }
+        }
+    }
+}
+
+// One entry of a mode's remap table: "when this key reaches this value,
+// emit these events instead of forwarding it as-is".
+#[derive(Debug, Clone, Deserialize)]
+struct RemapEntry {
+    key: String,
+    value: i32,
+    emit: Vec<OutputEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ModeConfig {
+    name: String,
+    #[serde(default)]
+    remap: Vec<RemapEntry>,
     toggle_sequence: Vec<(String, u16)>,
-    default_mode: Mode,
+    next_mode: String,
 }
 
-impl Config {
-    fn toggle_sequence_to_keystrokes(&self) -> Vec<Keystroke> {
+impl ModeConfig {
+    fn toggle_sequence_to_keystrokes(&self) -> Result<Vec<Keystroke>, anyhow::Error> {
         self.toggle_sequence
             .iter()
-            .map(|(k, v)| Keystroke {
-                key: Key::from_str(k).unwrap().0,
-                value: *v as i32,
+            .map(|(k, v)| {
+                let key = Key::from_str(k).map_err(|_| anyhow!("event \"{}\" doesn't exist", k))?;
+                Ok(Keystroke {
+                    key: key.0,
+                    value: *v as i32,
+                })
             })
             .collect()
     }
 }
 
-struct VirtualDeviceConfig {
+// A `ModeConfig` with its remap table and toggle sequence resolved to evdev
+// types once at startup, instead of parsing key names on every event.
+struct Mode {
     name: String,
-    keys: AttributeSet<Key>,
-    axes: AttributeSet<RelativeAxisType>,
+    remap: HashMap<(u16, i32), Vec<InputEvent>>,
+    toggle_sequence: Vec<Keystroke>,
+    next_mode: usize,
 }
 
-impl VirtualDeviceConfig {
-    fn new(name: String, keys: &[String], axes: &[String]) -> Result<Self, anyhow::Error> {
-        let keys = VirtualDeviceConfig::prepare_keys(keys)?;
-        let axes = VirtualDeviceConfig::prepare_axes(axes)?;
+impl Mode {
+    fn compile(
+        config: &ModeConfig,
+        index_by_name: &HashMap<&str, usize>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut remap = HashMap::new();
+        for entry in &config.remap {
+            let key = Key::from_str(&entry.key)
+                .map_err(|_| anyhow!("event \"{}\" doesn't exist", entry.key))?;
+            let outputs = entry
+                .emit
+                .iter()
+                .map(OutputEvent::to_input_event)
+                .collect::<Result<Vec<_>, _>>()?;
+            remap.insert((key.0, entry.value), outputs);
+        }
 
-        Ok(Self { name, keys, axes })
+        let next_mode = *index_by_name.get(config.next_mode.as_str()).ok_or_else(|| {
+            anyhow!(
+                "mode \"{}\" has unknown next_mode \"{}\"",
+                config.name,
+                config.next_mode
+            )
+        })?;
+
+        Ok(Self {
+            name: config.name.clone(),
+            remap,
+            toggle_sequence: config.toggle_sequence_to_keystrokes()?,
+            next_mode,
+        })
     }
+}
 
-    // TODO: open an issue on evdev_rs repo::
-    // evdev::attribute_set::ArrayedEvdevEnum is private so I can't use it in
-    // a trait bound and make these functions generic
-    fn prepare_keys(list: &[String]) -> Result<AttributeSet<Key>, anyhow::Error> {
-        let mut result = AttributeSet::<Key>::new();
-        for item in list.iter() {
-            if let Ok(converted) = Key::from_str(item) {
-                result.insert(converted);
-            } else {
-                return Err(anyhow!("event \"{}\" doesn't exist", item));
-            }
+fn compile_modes(modes: &[ModeConfig]) -> Result<Vec<Mode>, anyhow::Error> {
+    let index_by_name: HashMap<&str, usize> = modes
+        .iter()
+        .enumerate()
+        .map(|(i, mode)| (mode.name.as_str(), i))
+        .collect();
+
+    modes
+        .iter()
+        .map(|mode| Mode::compile(mode, &index_by_name))
+        .collect()
+}
+
+// Looks up the active mode's remap table for this event and returns what
+// should be emitted in its place, falling back to passing the event through
+// unchanged when the mode doesn't say anything about it.
+fn translate(event: &InputEvent, mode: &Mode) -> Vec<InputEvent> {
+    if let InputEventKind::Key(key) = event.kind() {
+        if let Some(outputs) = mode.remap.get(&(key.code(), event.value())) {
+            return outputs.clone();
         }
-        Ok(result)
     }
-    fn prepare_axes(list: &[String]) -> Result<AttributeSet<RelativeAxisType>, anyhow::Error> {
-        let mut result = AttributeSet::<RelativeAxisType>::new();
-        for item in list.iter() {
-            if let Ok(converted) = RelativeAxisType::from_str(item) {
-                result.insert(converted);
-            } else {
-                return Err(anyhow!("event \"{}\" doesn't exist", item));
-            }
-        }
-        Ok(result)
+    vec![*event]
+}
+
+// Where the virtual device's bus/vendor/product/version identity comes
+// from. Left unset, it defaults to a generic USB mouse identity so
+// downstream tools like libinput recognize it as a pointer device instead
+// of a nameless uinput node.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+enum InputIdConfig {
+    Fixed {
+        bus: String,
+        vendor: u16,
+        product: u16,
+        version: u16,
+    },
+    // Reuse the first grabbed device's identity, e.g. so the fold is still
+    // treated like the real keyboard it replaces.
+    MirrorTarget,
+}
+
+fn parse_bus_type(name: &str) -> Result<BusType, anyhow::Error> {
+    match name {
+        "BUS_USB" => Ok(BusType::BUS_USB),
+        "BUS_BLUETOOTH" => Ok(BusType::BUS_BLUETOOTH),
+        "BUS_VIRTUAL" => Ok(BusType::BUS_VIRTUAL),
+        "BUS_I8042" => Ok(BusType::BUS_I8042),
+        "BUS_PCI" => Ok(BusType::BUS_PCI),
+        other => Err(anyhow!("bus type \"{}\" doesn't exist", other)),
     }
 }
 
-// We need this because evdev library has no trait From<Key>/trait From<RelativeAxisType> for String,
-// so we have to store AttributeSet representation of it along with the  device.
-// Another reason to do that is because for some reason virtual devices
-// don't provide device.supported_*() methods.
+// Arbitrary placeholder ids for a generic USB mouse: not allocated to
+// anyone, just enough for libinput to apply pointer acceleration.
+const DEFAULT_VENDOR_ID: u16 = 0x0001;
+const DEFAULT_MOUSE_PRODUCT_ID: u16 = 0x0001;
+const DEFAULT_VERSION: u16 = 1;
+
+// How long to wait before retrying a failed reconnect attempt. Without this,
+// a persistent failure (e.g. /dev/input unwatchable due to permissions or an
+// inotify instance limit) spins the supervisor loop at 100% CPU instead of
+// degrading gracefully.
+const RECONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+fn resolve_input_id(
+    config: &Option<InputIdConfig>,
+    target_devices: &[RawDevice],
+) -> Result<InputId, anyhow::Error> {
+    match config {
+        Some(InputIdConfig::Fixed {
+            bus,
+            vendor,
+            product,
+            version,
+        }) => Ok(InputId::new(parse_bus_type(bus)?, *vendor, *product, *version)),
+        Some(InputIdConfig::MirrorTarget) => Ok(target_devices
+            .first()
+            .ok_or_else(|| anyhow!("no target devices to mirror the input_id of"))?
+            .input_id()),
+        None => Ok(InputId::new(
+            BusType::BUS_USB,
+            DEFAULT_VENDOR_ID,
+            DEFAULT_MOUSE_PRODUCT_ID,
+            DEFAULT_VERSION,
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    target_names: Vec<String>,
+    virtual_device_prefix: String,
+    #[serde(default)]
+    input_id: Option<InputIdConfig>,
+    modes: Vec<ModeConfig>,
+    default_mode: String,
+    /// Path to a Dyon script invoked for every event; absent by default,
+    /// which keeps the scripting subsystem a complete no-op.
+    #[serde(default)]
+    script: Option<PathBuf>,
+    /// Extra keys a script's `emit` calls may synthesize, beyond whatever
+    /// the grabbed devices or modes' remap tables already cover. Needed
+    /// because the virtual device's capabilities are fixed at startup, but
+    /// a script can call `emit` with any key name at runtime.
+    #[serde(default)]
+    script_keys: Vec<String>,
+    /// Extra relative axes a script's `emit_rel` calls may synthesize.
+    #[serde(default)]
+    script_axes: Vec<String>,
+}
+
+struct VirtualDeviceConfig {
+    name: String,
+    keys: AttributeSet<Key>,
+    axes: AttributeSet<RelativeAxisType>,
+    input_id: InputId,
+}
+
 struct VirtualDeviceWrapper {
     device: VirtualDevice,
-    config: VirtualDeviceConfig,
 }
 
 fn find_device(target_name: &str) -> Result<raw_stream::RawDevice, anyhow::Error> {
-    for device in raw_stream::enumerate() {
+    for (_, device) in raw_stream::enumerate() {
         if let Some(name) = device.name() {
             if name == target_name {
                 return Ok(device);
@@ -104,6 +280,26 @@ fn find_device(target_name: &str) -> Result<raw_stream::RawDevice, anyhow::Error
     Err(anyhow!("failed to find device \"{}\"", target_name))
 }
 
+fn find_devices(target_names: &[String]) -> Result<Vec<RawDevice>, anyhow::Error> {
+    target_names.iter().map(|name| find_device(name)).collect()
+}
+
+fn list_devices() {
+    for (path, device) in raw_stream::enumerate() {
+        println!("{}", path.display());
+        println!("  name: {}", device.name().unwrap_or("unknown"));
+        if let Some(keys) = device.supported_keys() {
+            let keys: Vec<String> = keys.iter().map(|key| format!("{:?}", key)).collect();
+            println!("  keys: {}", keys.join(", "));
+        }
+        if let Some(axes) = device.supported_relative_axes() {
+            let axes: Vec<String> = axes.iter().map(|axis| format!("{:?}", axis)).collect();
+            println!("  rel axes: {}", axes.join(", "));
+        }
+        println!();
+    }
+}
+
 fn prefix_device_name<'a>(prefix: &'a str, name: &'a str) -> String {
     format!("{} {}", prefix, name)
 }
@@ -111,67 +307,193 @@ fn prefix_device_name<'a>(prefix: &'a str, name: &'a str) -> String {
 fn create_virtual_device(
     device_config: &VirtualDeviceConfig,
 ) -> Result<VirtualDevice, anyhow::Error> {
-    let VirtualDeviceConfig { name, keys, axes } = device_config;
+    let VirtualDeviceConfig {
+        name,
+        keys,
+        axes,
+        input_id,
+    } = device_config;
 
     let device = VirtualDeviceBuilder::new()?
         .name(&name)
+        .input_id(*input_id)
         .with_keys(keys)?
         .with_relative_axes(axes)?
         .build()?;
     Ok(device)
 }
 
+// The virtual device must be able to emit every key/axis any mode can ever
+// produce, in addition to whatever the grabbed devices natively support, or
+// `with_keys`/`with_relative_axes` would reject an event from a mode's
+// remap table at emit time.
+fn widen_with_mode_outputs(
+    keys: &mut AttributeSet<Key>,
+    axes: &mut AttributeSet<RelativeAxisType>,
+    modes: &[Mode],
+) {
+    for mode in modes {
+        for outputs in mode.remap.values() {
+            for event in outputs {
+                match event.kind() {
+                    InputEventKind::Key(key) => keys.insert(key),
+                    InputEventKind::RelAxis(axis) => axes.insert(axis),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// Same idea as `widen_with_mode_outputs`, but for the `script_keys`/
+// `script_axes` config knob: a script's `emit`/`emit_rel` calls aren't
+// statically known, so the user has to declare what they may produce.
+fn widen_with_script_outputs(
+    keys: &mut AttributeSet<Key>,
+    axes: &mut AttributeSet<RelativeAxisType>,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    for code in &config.script_keys {
+        let key =
+            Key::from_str(code).map_err(|_| anyhow!("event \"{}\" doesn't exist", code))?;
+        keys.insert(key);
+    }
+    for code in &config.script_axes {
+        let axis = RelativeAxisType::from_str(code)
+            .map_err(|_| anyhow!("event \"{}\" doesn't exist", code))?;
+        axes.insert(axis);
+    }
+    Ok(())
+}
+
 fn setup(
     config: &Config,
-) -> Result<(RawDevice, VirtualDeviceWrapper, VirtualDeviceWrapper), anyhow::Error> {
-    let mut target_device = find_device(&config.target_name)?;
-    let target_device_name = String::from(target_device.name().unwrap());
-    target_device.grab().unwrap();
-
-    let mut target_device_keys = AttributeSet::<Key>::new();
-    for key in target_device.supported_keys().unwrap().iter() {
-        target_device_keys.insert(key);
-    }
-    let mut target_device_axes = AttributeSet::<RelativeAxisType>::new();
-    for axis in target_device.supported_relative_axes().unwrap().iter() {
-        target_device_axes.insert(axis);
-    }
-    let virtual_manipulator_config = VirtualDeviceConfig {
-        name: prefix_device_name(&config.virtual_manipulator_prefix, &target_device_name),
-        keys: target_device_keys,
-        axes: target_device_axes,
+    modes: &[Mode],
+) -> Result<(Vec<RawDevice>, VirtualDeviceWrapper), anyhow::Error> {
+    let mut target_devices = find_devices(&config.target_names)?;
+
+    let mut target_device_names = Vec::new();
+    let mut virtual_device_keys = AttributeSet::<Key>::new();
+    let mut virtual_device_axes = AttributeSet::<RelativeAxisType>::new();
+    for target_device in target_devices.iter_mut() {
+        let name = target_device
+            .name()
+            .ok_or_else(|| anyhow!("target device has no name"))?
+            .to_string();
+        target_device
+            .grab()
+            .with_context(|| format!("failed to grab target device \"{}\"", name))?;
+        target_device_names.push(name);
+
+        if let Some(keys) = target_device.supported_keys() {
+            for key in keys.iter() {
+                virtual_device_keys.insert(key);
+            }
+        }
+        if let Some(axes) = target_device.supported_relative_axes() {
+            for axis in axes.iter() {
+                virtual_device_axes.insert(axis);
+            }
+        }
+    }
+    widen_with_mode_outputs(&mut virtual_device_keys, &mut virtual_device_axes, modes);
+    widen_with_script_outputs(&mut virtual_device_keys, &mut virtual_device_axes, config)?;
+    let input_id = resolve_input_id(&config.input_id, &target_devices)?;
+
+    let target_device_name = target_device_names.join(" + ");
+    let virtual_device_config = VirtualDeviceConfig {
+        name: prefix_device_name(&config.virtual_device_prefix, &target_device_name),
+        keys: virtual_device_keys,
+        axes: virtual_device_axes,
+        input_id,
     };
-    let virtual_manipulator_device = create_virtual_device(&virtual_manipulator_config)?;
-
-    let virtual_mouse_config = VirtualDeviceConfig::new(
-        prefix_device_name(&config.virtual_mouse_prefix, &target_device_name),
-        &config.virtual_mouse_keys,
-        &config.virtual_mouse_axes,
-    )?;
-    let virtual_mouse_device = create_virtual_device(&virtual_mouse_config)?;
-
-    Ok((
-        target_device,
-        VirtualDeviceWrapper {
-            device: virtual_manipulator_device,
-            config: virtual_manipulator_config,
-        },
-        VirtualDeviceWrapper {
-            device: virtual_mouse_device,
-            config: virtual_mouse_config,
-        },
-    ))
-}
-
-fn should_emit(device: &VirtualDeviceWrapper, event: &InputEvent, mode: &Mode) -> bool {
-    if *mode == Mode::Manipulator {
-        return true;
+    let virtual_device = create_virtual_device(&virtual_device_config)?;
+
+    Ok((target_devices, VirtualDeviceWrapper { device: virtual_device }))
+}
+
+// Runs a single event through whichever remap path is active (script if
+// configured, the current mode's static `remap` table otherwise), applying
+// any mode switch the script requested along the way. Shared between the
+// normal per-event path and the SYN_DROPPED resync below, so a synthesized
+// key transition is remapped exactly like a physical one would be.
+fn translate_or_script(
+    event: &InputEvent,
+    modes: &[Mode],
+    current: &mut usize,
+    history: &VecDeque<Keystroke>,
+    script: &mut Option<Script>,
+) -> Vec<InputEvent> {
+    if let Some(script) = script.as_mut() {
+        match script.on_event(event, &modes[*current].name, history) {
+            Ok(output) => {
+                if let Some(name) = output.mode_change {
+                    match modes.iter().position(|mode| mode.name == name) {
+                        Some(idx) => *current = idx,
+                        None => println!("script requested unknown mode \"{}\"", name),
+                    }
+                }
+                output.events
+            }
+            Err(err) => {
+                println!("script failed on event, passing it through: {}", err);
+                vec![*event]
+            }
+        }
+    } else {
+        translate(event, &modes[*current])
     }
-    match event.kind() {
-        InputEventKind::Key(key) => device.config.keys.contains(key),
-        InputEventKind::RelAxis(axis) => device.config.axes.contains(axis),
-        _ => true,
+}
+
+// SYN_DROPPED means the kernel's evdev buffer overflowed and whatever we
+// forwarded since the last good SYN_REPORT can no longer be trusted, so a
+// key the physical device released may still be held down on the virtual
+// one. Re-query the authoritative key state and synthesize whatever
+// presses/releases are needed to converge, running each one through the
+// same remap path a normal event takes so a mode that remaps this key to
+// something else (e.g. mouse motion) doesn't end up resyncing the wrong
+// thing. Relative axes carry no state of their own (they're deltas, not
+// positions), so there's nothing to resync there.
+fn resync_key_state(
+    target_device: &mut RawDevice,
+    output_device: &mut VirtualDeviceWrapper,
+    key_state: &mut AttributeSet<Key>,
+    modes: &[Mode],
+    current: &mut usize,
+    history: &VecDeque<Keystroke>,
+    script: &mut Option<Script>,
+) {
+    let new_key_state = match target_device.get_key_state() {
+        Ok(state) => state,
+        Err(err) => {
+            println!("failed to resync key state after SYN_DROPPED: {}", err);
+            return;
+        }
+    };
+
+    let mut resync_events = Vec::new();
+    for key in new_key_state.iter() {
+        if !key_state.contains(key) {
+            resync_events.push(InputEvent::new(EventType::KEY, key.code(), 1));
+        }
     }
+    for key in key_state.iter() {
+        if !new_key_state.contains(key) {
+            resync_events.push(InputEvent::new(EventType::KEY, key.code(), 0));
+        }
+    }
+
+    if !resync_events.is_empty() {
+        println!("SYN_DROPPED: resyncing {} key(s)", resync_events.len());
+        for resync_event in &resync_events {
+            let outputs = translate_or_script(resync_event, modes, current, history, script);
+            if let Err(err) = output_device.device.emit(&outputs) {
+                println!("failed to emit resync events: {}", err);
+            }
+        }
+    }
+
+    *key_state = new_key_state;
 }
 
 fn should_toggle(history: &VecDeque<Keystroke>, sequence: &[Keystroke]) -> bool {
@@ -203,53 +525,257 @@ fn save_stroke(history: &mut VecDeque<Keystroke>, event: &InputEvent, max_len: u
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config: Config =
-        serde_yaml::from_str(include_str!("../config.yml")).context("config.yml is malformed")?;
-    let device = find_device(&config.target_name)?;
-    println!("target device configuration: {:#?}", device);
+// Blocks until every device in `target_names` shows up under /dev/input, by
+// watching the directory for IN_CREATE (a fresh /dev/input/eventN node)
+// rather than polling. IN_DELETE is watched too, but only IN_CREATE events
+// trigger a re-check of whether every target is present.
+fn wait_for_devices(target_names: &[String]) -> Result<(), anyhow::Error> {
+    let inotify = Inotify::init(InitFlags::empty()).context("failed to init inotify")?;
+    inotify
+        .add_watch(
+            "/dev/input",
+            AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE,
+        )
+        .context("failed to watch /dev/input")?;
 
-    let (mut target_device, mut virtual_manipulator_device, mut virtual_mouse_device) =
-        setup(&config).context("failed to create virtual devices")?;
+    let all_present = |names: &[String]| names.iter().all(|name| find_device(name).is_ok());
 
-    let mut mode = config.default_mode;
+    if all_present(target_names) {
+        return Ok(());
+    }
 
-    let toggle_sequence = config.toggle_sequence_to_keystrokes();
-    let history_max_len: usize = toggle_sequence.len();
-    let mut history: VecDeque<Keystroke> = VecDeque::new();
+    loop {
+        let events = inotify
+            .read_events()
+            .context("failed to read inotify events")?;
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_CREATE) && all_present(target_names) {
+                return Ok(());
+            }
+        }
+    }
+}
 
-    //    let history = ringbuf::RingBuffer::<Keystroke>::new(toggle_sequence.len());
-    //    let (mut history_producer, mut history_consumer) = history.split();
+// Runs the forward/toggle loop until one of the target devices errors out
+// (unplug, suspend, Bluetooth drop), returning the mode index that was
+// active at that point so the caller can resume in the same mode once it
+// reconnects. Several physical devices can feed the same virtual device, so
+// their fds are multiplexed with poll() and the toggle/mode state is shared
+// across all of them.
+fn run(
+    target_devices: &mut [RawDevice],
+    output_device: &mut VirtualDeviceWrapper,
+    modes: &[Mode],
+    mut current: usize,
+    script: &mut Option<Script>,
+) -> usize {
+    let mut history: VecDeque<Keystroke> = VecDeque::new();
+    // One cache per grabbed device, so a SYN_DROPPED resync of one device
+    // is diffed only against the keys that device itself forwarded.
+    let mut key_states: Vec<AttributeSet<Key>> = target_devices
+        .iter()
+        .map(|_| AttributeSet::<Key>::new())
+        .collect();
 
     loop {
-        let output_device = match mode {
-            Mode::Manipulator => &mut virtual_manipulator_device,
-            Mode::Mouse => &mut virtual_mouse_device,
-        };
-        let events = target_device
-            .fetch_events()
-            .context("failed to fetch events")?;
-        for event in events {
-            let stroke_saved = save_stroke(&mut history, &event, history_max_len);
-            if should_emit(output_device, &event, &mode) {
-                println!("emitting event: {:#?}", event);
-                let _ = output_device
-                    .device
-                    .emit(&[event])
-                    .context("failed to emit an event")?;
+        let mut poll_fds: Vec<PollFd> = target_devices
+            .iter()
+            .map(|device| PollFd::new(device.as_raw_fd(), PollFlags::POLLIN))
+            .collect();
+        if let Err(err) = poll(&mut poll_fds, -1) {
+            println!("poll on target devices failed: {}", err);
+            return current;
+        }
+
+        for (i, poll_fd) in poll_fds.iter().enumerate() {
+            let revents = poll_fd.revents().unwrap_or_else(PollFlags::empty);
+            if revents.intersects(PollFlags::POLLERR | PollFlags::POLLHUP | PollFlags::POLLNVAL) {
+                println!("lost a target device");
+                return current;
+            }
+            if !revents.contains(PollFlags::POLLIN) {
+                continue;
             }
-            if stroke_saved && should_toggle(&history, &toggle_sequence) {
-                mode = match mode {
-                    Mode::Mouse => {
-                        println!("mouse mode is switching to manipulator");
-                        Mode::Manipulator
+
+            let target_device = &mut target_devices[i];
+            // Collect instead of forwarding the borrowed iterator directly
+            // so `target_device` is free again by the time we need to
+            // re-query it for a SYN_DROPPED resync below.
+            let events: Vec<InputEvent> = match target_device.fetch_events() {
+                Ok(events) => events.collect(),
+                Err(err) => {
+                    println!("lost target device: {}", err);
+                    return current;
+                }
+            };
+
+            for event in events {
+                if let InputEventKind::Synchronization(Synchronization::SYN_DROPPED) = event.kind()
+                {
+                    resync_key_state(
+                        target_device,
+                        output_device,
+                        &mut key_states[i],
+                        modes,
+                        &mut current,
+                        &history,
+                        script,
+                    );
+                    // a dropped batch may have swallowed part of an in-progress
+                    // toggle sequence, so don't let a stale prefix false-trigger
+                    history.clear();
+                    continue;
+                }
+                if let InputEventKind::Key(key) = event.kind() {
+                    if event.value() == 0 {
+                        key_states[i].remove(key);
+                    } else {
+                        key_states[i].insert(key);
                     }
-                    Mode::Manipulator => {
-                        println!("manipulator mode is switching to mouse");
-                        Mode::Mouse
+                }
+
+                let history_max_len = modes[current].toggle_sequence.len();
+                let stroke_saved = save_stroke(&mut history, &event, history_max_len);
+
+                // With a script configured it takes over entirely for this
+                // event - including whether to swallow it - in place of the
+                // built-in remap table; absent one, `translate` runs as
+                // before.
+                let mode_before = current;
+                let outputs = translate_or_script(&event, modes, &mut current, &history, script);
+                if current != mode_before {
+                    // don't let a sequence accumulated under the old mode
+                    // false-trigger a toggle in the new one
+                    history.clear();
+                }
+
+                for output in outputs {
+                    println!("emitting event: {:#?}", output);
+                    if let Err(err) = output_device.device.emit(&[output]) {
+                        println!("failed to emit an event: {}", err);
                     }
-                };
+                }
+                if stroke_saved && should_toggle(&history, &modes[current].toggle_sequence) {
+                    let next = modes[current].next_mode;
+                    println!(
+                        "mode \"{}\" is switching to \"{}\"",
+                        modes[current].name, modes[next].name
+                    );
+                    current = next;
+                    history.clear();
+                }
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    if let Some(Commands::ListDevices) = cli.command {
+        list_devices();
+        return Ok(());
+    }
+
+    let config_str = std::fs::read_to_string(&cli.config)
+        .with_context(|| format!("failed to read config file \"{}\"", cli.config.display()))?;
+    let config: Config = serde_yaml::from_str(&config_str).context("config is malformed")?;
+    let modes = compile_modes(&config.modes).context("failed to compile modes")?;
+
+    let mut current = modes
+        .iter()
+        .position(|mode| mode.name == config.default_mode)
+        .ok_or_else(|| anyhow!("default_mode \"{}\" is not one of modes", config.default_mode))?;
+
+    let mut script = config
+        .script
+        .as_deref()
+        .map(Script::load)
+        .transpose()
+        .context("failed to load script")?;
+
+    loop {
+        if let Err(err) = wait_for_devices(&config.target_names) {
+            println!("failed waiting for target devices: {}", err);
+            thread::sleep(RECONNECT_RETRY_DELAY);
+            continue;
+        }
+
+        let (mut target_devices, mut output_device) = match setup(&config, &modes) {
+            Ok(setup) => setup,
+            Err(err) => {
+                println!("failed to create the virtual device: {}", err);
+                thread::sleep(RECONNECT_RETRY_DELAY);
+                continue;
             }
+        };
+        println!("target device configuration: {:#?}", target_devices);
+
+        current = run(
+            &mut target_devices,
+            &mut output_device,
+            &modes,
+            current,
+            &mut script,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode_config(name: &str, remap: Vec<RemapEntry>, next_mode: &str) -> ModeConfig {
+        ModeConfig {
+            name: name.to_string(),
+            remap,
+            toggle_sequence: vec![("KEY_LEFTCTRL".to_string(), 1)],
+            next_mode: next_mode.to_string(),
         }
     }
+
+    #[test]
+    fn translate_passes_through_unmapped_events() {
+        let modes = compile_modes(&[mode_config("default", vec![], "default")]).unwrap();
+        let event = InputEvent::new(EventType::KEY, Key::KEY_A.code(), 1);
+        assert_eq!(translate(&event, &modes[0]), vec![event]);
+    }
+
+    #[test]
+    fn translate_applies_the_mode_remap_table() {
+        let remap = vec![RemapEntry {
+            key: "KEY_J".to_string(),
+            value: 1,
+            emit: vec![OutputEvent::RelAxis {
+                code: "REL_Y".to_string(),
+                value: 10,
+            }],
+        }];
+        let modes = compile_modes(&[mode_config("default", remap, "default")]).unwrap();
+        let event = InputEvent::new(EventType::KEY, Key::KEY_J.code(), 1);
+        let expected = InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, 10);
+        assert_eq!(translate(&event, &modes[0]), vec![expected]);
+    }
+
+    #[test]
+    fn compile_modes_rejects_an_unknown_remap_key() {
+        let remap = vec![RemapEntry {
+            key: "NOT_A_REAL_KEY".to_string(),
+            value: 1,
+            emit: vec![],
+        }];
+        assert!(compile_modes(&[mode_config("default", remap, "default")]).is_err());
+    }
+
+    #[test]
+    fn compile_modes_rejects_an_unknown_next_mode() {
+        assert!(compile_modes(&[mode_config("default", vec![], "missing")]).is_err());
+    }
+
+    #[test]
+    fn compile_modes_rejects_an_unknown_toggle_sequence_key() {
+        let mut config = mode_config("default", vec![], "default");
+        config.toggle_sequence = vec![("NOT_A_REAL_KEY".to_string(), 1)];
+        assert!(compile_modes(&[config]).is_err());
+    }
 }
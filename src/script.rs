@@ -0,0 +1,161 @@
+// Optional embedded scripting hook: when a config names a script file, it is
+// loaded once at startup and then invoked for every event read by
+// `fetch_events` (and every key event a SYN_DROPPED resync synthesizes) in
+// place of the built-in `translate` path, so it can do remaps the
+// static config can't express (chords, tap-vs-hold, context-dependent
+// behavior) without patching this crate.
+use anyhow::{anyhow, Context};
+use dyon::{Dfn, Lt, Module, Runtime, Type, Variable};
+use evdev::{EventType, InputEvent, InputEventKind, Key, RelativeAxisType};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::Keystroke;
+
+/// What a script call produced: events to emit in place of the original
+/// one, and an optional mode switch it asked for.
+#[derive(Debug, Default)]
+pub struct ScriptOutput {
+    pub events: Vec<InputEvent>,
+    pub mode_change: Option<String>,
+}
+
+thread_local! {
+    // The script's `emit`/`emit_rel`/`set_mode` calls are plain external
+    // functions with no way to return data to the caller, so they deposit
+    // their side effects here for `Script::on_event` to drain once the call
+    // returns.
+    static PENDING: RefCell<ScriptOutput> = RefCell::new(ScriptOutput::default());
+}
+
+fn emit(rt: &mut Runtime) -> Result<(), String> {
+    let value: f64 = rt.pop()?;
+    let code: String = rt.pop()?;
+    let key = Key::from_str(&code).map_err(|_| format!("event \"{}\" doesn't exist", code))?;
+    PENDING.with(|pending| {
+        pending
+            .borrow_mut()
+            .events
+            .push(InputEvent::new(EventType::KEY, key.code(), value as i32));
+    });
+    Ok(())
+}
+
+fn emit_rel(rt: &mut Runtime) -> Result<(), String> {
+    let delta: f64 = rt.pop()?;
+    let code: String = rt.pop()?;
+    let axis = RelativeAxisType::from_str(&code)
+        .map_err(|_| format!("event \"{}\" doesn't exist", code))?;
+    PENDING.with(|pending| {
+        pending
+            .borrow_mut()
+            .events
+            .push(InputEvent::new(EventType::RELATIVE, axis.0, delta as i32));
+    });
+    Ok(())
+}
+
+fn set_mode(rt: &mut Runtime) -> Result<(), String> {
+    let name: String = rt.pop()?;
+    PENDING.with(|pending| pending.borrow_mut().mode_change = Some(name));
+    Ok(())
+}
+
+fn module_with_hooks() -> Module {
+    let mut module = Module::new();
+    module.add(
+        Arc::new("emit".into()),
+        emit,
+        Dfn {
+            lts: vec![Lt::Default, Lt::Default],
+            tys: vec![Type::Str, Type::F64],
+            ret: Type::Void,
+        },
+    );
+    module.add(
+        Arc::new("emit_rel".into()),
+        emit_rel,
+        Dfn {
+            lts: vec![Lt::Default, Lt::Default],
+            tys: vec![Type::Str, Type::F64],
+            ret: Type::Void,
+        },
+    );
+    module.add(
+        Arc::new("set_mode".into()),
+        set_mode,
+        Dfn {
+            lts: vec![Lt::Default],
+            tys: vec![Type::Str],
+            ret: Type::Void,
+        },
+    );
+    module
+}
+
+pub struct Script {
+    module: Arc<Module>,
+    runtime: Runtime,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let mut module = module_with_hooks();
+        dyon::load(&path.display().to_string(), &mut module)
+            .map_err(|err| anyhow!("{}", err))
+            .with_context(|| format!("failed to load script \"{}\"", path.display()))?;
+        Ok(Self {
+            module: Arc::new(module),
+            runtime: Runtime::new(),
+        })
+    }
+
+    /// Calls the script's `on_event(kind, code, value, mode, history)` with
+    /// the decoded event, the active mode's name, and the toggle-sequence
+    /// history so far, then drains whatever `emit`/`emit_rel`/`set_mode`
+    /// recorded during that call.
+    pub fn on_event(
+        &mut self,
+        event: &InputEvent,
+        mode: &str,
+        history: &VecDeque<Keystroke>,
+    ) -> Result<ScriptOutput, anyhow::Error> {
+        let (kind, code) = match event.kind() {
+            InputEventKind::Key(key) => ("key", key.code() as f64),
+            InputEventKind::RelAxis(axis) => ("rel_axis", axis.0 as f64),
+            _ => ("other", event.code() as f64),
+        };
+        let history_arg = Variable::Array(Arc::new(RefCell::new(
+            history
+                .iter()
+                .map(|stroke| {
+                    Variable::Array(Arc::new(RefCell::new(vec![
+                        Variable::f64(stroke.key as f64),
+                        Variable::f64(stroke.value as f64),
+                    ])))
+                })
+                .collect(),
+        )));
+
+        PENDING.with(|pending| *pending.borrow_mut() = ScriptOutput::default());
+
+        self.runtime
+            .call_str_ret(
+                &self.module,
+                "on_event",
+                &[
+                    Variable::str(kind),
+                    Variable::f64(code),
+                    Variable::f64(event.value() as f64),
+                    Variable::str(mode),
+                    history_arg,
+                ],
+            )
+            .map_err(|err| anyhow!("script error in on_event: {}", err))?;
+
+        Ok(PENDING.with(|pending| pending.replace(ScriptOutput::default())))
+    }
+}